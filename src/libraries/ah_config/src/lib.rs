@@ -18,196 +18,882 @@
 //  * Default values (least priority)
 //  * Configuration file (toml format)
 //  * Environment variables
-//  * Command line arguments (highest priority) TODO: Implement command line arguments. The config crate doesn't do this out of the box
+//  * Command line arguments (highest priority)
 //  If no config file is provided whatever value is picked will be written to the default config file
 //  If a config file is provided, and a higher priority value is provided via environment variable or command line argument, the value will be written to the config file
+//  `--config-file` is resolved before any other source is read, since it determines which file the rest of the config is merged from
+//  Nested tables ([acars], [vdlm2], [hfdl]) follow the same ordering. An `AH_*` environment
+//  override for a nested field doubles its separator, e.g. `AH_ACARS__PORT` sets `acars.port`
 
 /// ACARS Hub valid configuration options
 /// database_url: The URL to the database
-/// enable_acars: Enable ACARS processing
-/// enable_vdlm2: Enable VDL-M2 processing
-/// enable_hfdl: Enable HFDL processing
+/// acars: Connection details for the acarsdec feed (`[acars]`: enabled, host, port, format)
+/// vdlm2: Connection details for the dumpvdl2 feed (`[vdlm2]`: enabled, host, port, format)
+/// hfdl: Connection details for the dumphfdl feed (`[hfdl]`: enabled, host, port, format)
 /// enable_iridium: Enable Iridium processing
 /// enable_inmarsat: Enable Inmarsat processing
 /// enable_adsb: Enable ADS-B processing
 /// log_level: The log level. Valid values are: trace, debug, info, warn, error. Default is info. List is ordered from most verbose to least verbos
+use clap::Parser;
 use config::Config;
 use log::info;
 use sdre_rust_logging::SetupLogging;
-use std::collections::HashMap;
+
+/// The log levels `log_level` is allowed to be, ordered from most to least verbose.
+const VALID_LOG_LEVELS: [&str; 5] = ["trace", "debug", "info", "warn", "error"];
+
+/// Everything that can go wrong while resolving or persisting the config, so a typo in
+/// `ah_config.toml` produces a message pointing at the offending key instead of a panic.
+#[derive(Debug)]
+pub enum AhConfigError {
+    /// Reading or writing the config file failed
+    Io(std::io::Error),
+    /// The `config` crate failed to parse or merge a source
+    ConfigParse(config::ConfigError),
+    /// A field that expects `true`/`false` had something else in it
+    InvalidBool { key: String, value: String },
+    /// `log_level` wasn't one of the documented values
+    InvalidLogLevel { value: String },
+    /// A source's `format` field wasn't `json` or `raw`
+    InvalidSourceFormat { value: String },
+    /// A source's `port` field didn't fit in a `u16`
+    InvalidPort { key: String, value: i64 },
+}
+
+impl std::fmt::Display for AhConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AhConfigError::Io(err) => write!(f, "config io error: {err}"),
+            AhConfigError::ConfigParse(err) => write!(f, "config file error: {err}"),
+            AhConfigError::InvalidBool { key, value } => write!(
+                f,
+                "invalid value for `{key}`: expected true or false, got `{value}`"
+            ),
+            AhConfigError::InvalidLogLevel { value } => write!(
+                f,
+                "invalid log_level `{value}`: expected one of {}",
+                VALID_LOG_LEVELS.join(", ")
+            ),
+            AhConfigError::InvalidSourceFormat { value } => write!(
+                f,
+                "invalid format `{value}`: expected `json` or `raw`"
+            ),
+            AhConfigError::InvalidPort { key, value } => write!(
+                f,
+                "invalid value for `{key}`: {value} does not fit in a u16 port number"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for AhConfigError {}
+
+impl From<std::io::Error> for AhConfigError {
+    fn from(err: std::io::Error) -> Self {
+        AhConfigError::Io(err)
+    }
+}
+
+impl From<config::ConfigError> for AhConfigError {
+    fn from(err: config::ConfigError) -> Self {
+        AhConfigError::ConfigParse(err)
+    }
+}
+
+/// Where a resolved config value came from, ordered from lowest to highest priority.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConfigOrigin {
+    /// The compiled-in default, nothing overrode it
+    Default,
+    /// The toml config file
+    File,
+    /// An `AH_*` environment variable, named here for display purposes
+    Environment(String),
+    /// A command line flag
+    CommandLine,
+}
+
+impl std::fmt::Display for ConfigOrigin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigOrigin::Default => write!(f, "default"),
+            ConfigOrigin::File => write!(f, "config file"),
+            ConfigOrigin::Environment(var) => write!(f, "{var} env"),
+            ConfigOrigin::CommandLine => write!(f, "command line flag"),
+        }
+    }
+}
+
+impl ConfigOrigin {
+    /// Numeric priority of this origin, lowest to highest. Used to pick the winner when the
+    /// same field can be set through more than one key (e.g. a legacy flat key and a new
+    /// nested key), instead of letting whichever candidate is checked last win by accident.
+    fn rank(&self) -> u8 {
+        match self {
+            ConfigOrigin::Default => 0,
+            ConfigOrigin::File => 1,
+            ConfigOrigin::Environment(_) => 2,
+            ConfigOrigin::CommandLine => 3,
+        }
+    }
+}
+
+/// The feed payload format a decoder source is sending
+#[derive(Clone, Debug, PartialEq, Eq, Default)]
+pub enum SourceFormat {
+    #[default]
+    Json,
+    Raw,
+}
+
+impl std::fmt::Display for SourceFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SourceFormat::Json => write!(f, "json"),
+            SourceFormat::Raw => write!(f, "raw"),
+        }
+    }
+}
+
+impl std::str::FromStr for SourceFormat {
+    type Err = AhConfigError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "json" => Ok(SourceFormat::Json),
+            "raw" => Ok(SourceFormat::Raw),
+            _ => Err(AhConfigError::InvalidSourceFormat {
+                value: value.to_string(),
+            }),
+        }
+    }
+}
+
+/// Connection details for one of the decoder feeds (acarsdec/dumpvdl2/dumphfdl), stored as
+/// a nested table (e.g. `[acars]`) rather than a flat `enable_*` boolean.
+#[derive(Clone, Debug)]
+pub struct SourceConfig {
+    pub enabled: bool,
+    pub host: String,
+    pub port: u16,
+    pub format: SourceFormat,
+}
+
+impl SourceConfig {
+    fn with_default_port(port: u16) -> Self {
+        SourceConfig {
+            enabled: false,
+            host: "127.0.0.1".to_string(),
+            port,
+            format: SourceFormat::default(),
+        }
+    }
+}
+
+/// Per-field provenance for a [`SourceConfig`] - each of `enabled`/`host`/`port`/`format`
+/// can come from a different source, so they're tracked separately rather than collapsing
+/// the whole table to a single origin.
+#[derive(Clone, Debug)]
+pub struct SourceOrigins {
+    pub enabled: ConfigOrigin,
+    pub host: ConfigOrigin,
+    pub port: ConfigOrigin,
+    pub format: ConfigOrigin,
+}
+
+impl Default for SourceOrigins {
+    fn default() -> Self {
+        SourceOrigins {
+            enabled: ConfigOrigin::Default,
+            host: ConfigOrigin::Default,
+            port: ConfigOrigin::Default,
+            format: ConfigOrigin::Default,
+        }
+    }
+}
+
+/// Records where every field on [`AhConfig`] was ultimately resolved from, so a user
+/// asking "why is ACARS still disabled?" can be pointed at the exact source instead of
+/// having to guess across defaults, the config file, the environment, and the CLI.
+#[derive(Clone, Debug)]
+pub struct AhConfigSources {
+    pub database_url: ConfigOrigin,
+    pub acars: SourceOrigins,
+    pub vdlm2: SourceOrigins,
+    pub hfdl: SourceOrigins,
+    pub enable_iridium: ConfigOrigin,
+    pub enable_inmarsat: ConfigOrigin,
+    pub enable_adsb: ConfigOrigin,
+    pub log_level: ConfigOrigin,
+}
+
+impl Default for AhConfigSources {
+    fn default() -> Self {
+        AhConfigSources {
+            database_url: ConfigOrigin::Default,
+            acars: SourceOrigins::default(),
+            vdlm2: SourceOrigins::default(),
+            hfdl: SourceOrigins::default(),
+            enable_iridium: ConfigOrigin::Default,
+            enable_inmarsat: ConfigOrigin::Default,
+            enable_adsb: ConfigOrigin::Default,
+            log_level: ConfigOrigin::Default,
+        }
+    }
+}
+
+/// Command line arguments. These are the highest priority source and, when present,
+/// override any value pulled from the config file or the `AH_*` environment variables.
+#[derive(Parser, Debug, Default)]
+#[command(name = "acarshub", about = "ACARS Hub")]
+struct CliArgs {
+    /// Path to the config file. Resolved before any other source is read, since it
+    /// determines which file the rest of the config is merged from.
+    #[arg(long)]
+    config_file: Option<String>,
+
+    /// The URL to the database
+    #[arg(long)]
+    database_url: Option<String>,
+
+    /// Enable ACARS processing (`acars.enabled`)
+    #[arg(long)]
+    enable_acars: Option<bool>,
+
+    /// The log level. Valid values are: trace, debug, info, warn, error
+    #[arg(long)]
+    log_level: Option<String>,
+}
 
 pub struct AhConfig {
     pub database_url: String,
-    pub enable_acars: bool,
-    pub enable_vdlm2: bool,
-    pub enable_hfdl: bool,
+    pub acars: SourceConfig,
+    pub vdlm2: SourceConfig,
+    pub hfdl: SourceConfig,
     pub enable_iridium: bool,
     pub enable_inmarsat: bool,
     pub enable_adsb: bool,
     pub log_level: String,
     pub config_file: String,
+    pub sources: AhConfigSources,
 }
 
 impl Default for AhConfig {
     fn default() -> Self {
         AhConfig {
             database_url: "sqlite://acars.db".to_string(),
-            enable_acars: false,
-            enable_vdlm2: false,
-            enable_hfdl: false,
+            acars: SourceConfig::with_default_port(5550),
+            vdlm2: SourceConfig::with_default_port(5555),
+            hfdl: SourceConfig::with_default_port(5556),
             enable_iridium: false,
             enable_inmarsat: false,
             enable_adsb: false,
             log_level: "info".to_string(),
             config_file: AhConfig::get_file_path(),
+            sources: AhConfigSources::default(),
         }
     }
 }
 
 impl AhConfig {
-    pub fn new() -> Self {
+    pub fn new() -> Result<Self, AhConfigError> {
         AhConfig::get_and_validate_config()
     }
 
     fn get_file_path() -> String {
         // if we are in a test env (denoted with AH_TEST_ENV_PATH) we will use the test config file
-        // from the env variable. Otherwise, detect the platform and use "./ah_config.toml" for the config file
+        // from the env variable, and AH_CONFIG_PATH is for docker specifically. Both take
+        // priority over anything discovered on disk.
 
         if let Ok(path) = std::env::var("AH_TEST_ENV_PATH") {
-            path
-        } else if let Ok(path) = std::env::var("AH_CONFIG_PATH") {
-            // this match arm is for docker specifically
-            path
-        } else {
-            // FIXME: we should use platform specific paths
-            match std::env::consts::OS {
-                "linux" => "./ah_config.toml",
-                "macos" => "./ah_config.toml",
-                "windows" => "./ah_config.toml",
-                _ => "./ah_config.toml",
+            return path;
+        }
+
+        if let Ok(path) = std::env::var("AH_CONFIG_PATH") {
+            return path;
+        }
+
+        // prefer an already-existing config file in the platform config directory
+        // ($XDG_CONFIG_HOME/acarshub, Application Support, %APPDATA%, ...)
+        let platform_path = AhConfig::platform_config_path();
+        if std::path::Path::new(&platform_path).exists() {
+            return platform_path;
+        }
+
+        // otherwise walk up from the current directory looking for an existing config
+        // file, the way Cargo and Nix locate user config files
+        if let Some(found) = AhConfig::find_existing_config_upwards() {
+            return found;
+        }
+
+        // nothing on disk yet, so this is where a new one will be written
+        platform_path
+    }
+
+    /// The platform-appropriate location for the config file: `$XDG_CONFIG_HOME/acarshub`
+    /// (or `~/.config/acarshub`) on Linux, `Application Support/acarshub` on macOS, and
+    /// `%APPDATA%\acarshub` on Windows.
+    fn platform_config_path() -> String {
+        match dirs::config_dir() {
+            Some(dir) => dir
+                .join("acarshub")
+                .join("ah_config.toml")
+                .to_string_lossy()
+                .to_string(),
+            None => "./ah_config.toml".to_string(),
+        }
+    }
+
+    /// Walks upward from the current directory, one ancestor at a time, looking for an
+    /// existing `ah_config.toml` - mirrors how Cargo discovers `.cargo/config.toml`.
+    fn find_existing_config_upwards() -> Option<String> {
+        let mut dir = std::env::current_dir().ok()?;
+
+        loop {
+            let candidate = dir.join("ah_config.toml");
+            if candidate.exists() {
+                return Some(candidate.to_string_lossy().to_string());
             }
-            .to_string()
+
+            if !dir.pop() {
+                return None;
+            }
+        }
+    }
+
+    /// `--config-file` has to win before anything else is resolved, since it determines
+    /// which file the rest of the merge (defaults -> file -> env) reads from.
+    fn resolve_config_file_path(cli_args: &CliArgs) -> String {
+        match &cli_args.config_file {
+            Some(path) => path.clone(),
+            None => AhConfig::get_file_path(),
         }
     }
 
-    fn write_default_config(file_path: &str) {
+    fn write_default_config(file_path: &str) -> Result<(), AhConfigError> {
         // Lets see if the file exists
-        if !std::path::Path::new(&file_path).exists() {
+        let path = std::path::Path::new(file_path);
+        if !path.exists() {
+            // the resolved path may be a platform config directory that doesn't exist yet
+            if let Some(parent) = path.parent() {
+                if !parent.as_os_str().is_empty() {
+                    std::fs::create_dir_all(parent)?;
+                }
+            }
+
             // if the file does not exist, we will write the default config to the file
-            let default_config = r#"
-                        database_url = "sqlite://acars.db"
-                        enable_acars = false
-                        enable_vdlm2 = false
-                        enable_hfdl = false
-                        enable_iridium = false
-                        enable_inmarsat = false
-                        enable_adsb = false
-                        log_level = "info"
-                    "#;
-
-            std::fs::write(file_path, default_config).unwrap();
+            let default_config = AhConfig::default().get_config_as_toml_string();
+
+            std::fs::write(file_path, default_config)?;
 
             println!(
                 "Config file does not exist, creating it now at {}",
-                std::fs::canonicalize(file_path)
-                    .unwrap()
+                std::fs::canonicalize(file_path)?
                     .to_str()
-                    .unwrap()
+                    .unwrap_or(file_path)
                     .to_string()
             );
         }
+
+        Ok(())
+    }
+
+    /// Parses a field that the documented config schema says must be `true`/`false`,
+    /// returning a typed error (naming the offending key) instead of panicking.
+    fn parse_bool(key: &str, value: &str) -> Result<bool, AhConfigError> {
+        value
+            .parse::<bool>()
+            .map_err(|_| AhConfigError::InvalidBool {
+                key: key.to_string(),
+                value: value.to_string(),
+            })
+    }
+
+    /// `log_level` must be one of the values documented in the module header
+    fn validate_log_level(log_level: &str) -> Result<(), AhConfigError> {
+        if VALID_LOG_LEVELS.contains(&log_level) {
+            Ok(())
+        } else {
+            Err(AhConfigError::InvalidLogLevel {
+                value: log_level.to_string(),
+            })
+        }
     }
 
-    fn get_config(file_path: &str) -> Option<HashMap<String, String>> {
+    fn get_config(file_path: &str) -> Result<Config, AhConfigError> {
         // if we are in a test env (denoted with AH_TEST_ENV_PATH) we will use the test config file
         // from the env variable. Otherwise, detect the platform and use "./ah_config.toml" for the config file
 
-        AhConfig::write_default_config(file_path);
+        AhConfig::write_default_config(file_path)?;
 
         let config = Config::builder()
             .add_source(config::File::with_name(file_path))
-            .add_source(config::Environment::with_prefix("AH"))
-            .build()
-            .unwrap();
+            .add_source(
+                config::Environment::with_prefix("AH")
+                    // `config-rs` reuses `separator` for joining the prefix unless
+                    // `prefix_separator` is set independently, which would turn every
+                    // existing single-underscore override (e.g. `AH_DATABASE_URL`) into
+                    // `AH__DATABASE_URL` and silently stop matching it.
+                    .prefix_separator("_")
+                    .separator("__"),
+            )
+            .build()?;
+
+        Ok(config)
+    }
 
-        config.try_deserialize().unwrap()
+    /// `config` merges the file and the environment together, so to tell them apart we
+    /// check whether the matching `AH_*` environment variable is actually set. A nested
+    /// key's `.` becomes `__`, matching the `Environment` source's nested separator.
+    fn origin_for_key(key: &str) -> ConfigOrigin {
+        let env_key = key.to_uppercase().replace('.', "__");
+        let env_var = format!("AH_{env_key}");
+
+        if std::env::var(&env_var).is_ok() {
+            ConfigOrigin::Environment(env_var)
+        } else {
+            ConfigOrigin::File
+        }
     }
 
-    fn get_and_validate_config() -> AhConfig {
-        let file_path = AhConfig::get_file_path();
-        let config = AhConfig::get_config(&file_path).unwrap();
+    /// Resolves a field that may be set through more than one key (e.g. a legacy flat key
+    /// and a new nested key) by collecting every key that actually resolved and picking the
+    /// one with the highest-priority origin, rather than whichever `if let` runs last.
+    fn resolve_candidate(config: &Config, keys: &[&str]) -> Option<(String, ConfigOrigin)> {
+        keys.iter()
+            .filter_map(|key| {
+                config
+                    .get_string(key)
+                    .ok()
+                    .map(|value| (value, AhConfig::origin_for_key(key)))
+            })
+            // `max_by_key` keeps the last of equal maxima, so iterate in reverse to let the
+            // earliest key win a tie
+            .rev()
+            .max_by_key(|(_, origin)| origin.rank())
+    }
+
+    /// Resolves one of the `[acars]`/`[vdlm2]`/`[hfdl]` tables, honoring the old flat
+    /// `enable_*` key as an alias for `<table>.enabled` ranked by actual source priority
+    /// rather than by which key happens to be checked first.
+    fn resolve_source(
+        config: &Config,
+        mut source: SourceConfig,
+        table: &str,
+        legacy_enabled_key: &str,
+    ) -> Result<(SourceConfig, SourceOrigins), AhConfigError> {
+        let mut origins = SourceOrigins::default();
+
+        let enabled_key = format!("{table}.enabled");
+        if let Some((enabled, origin)) =
+            AhConfig::resolve_candidate(config, &[legacy_enabled_key, &enabled_key])
+        {
+            source.enabled = AhConfig::parse_bool(&enabled_key, &enabled)?;
+            origins.enabled = origin;
+        }
+
+        let host_key = format!("{table}.host");
+        if let Some((host, origin)) = AhConfig::resolve_candidate(config, &[&host_key]) {
+            source.host = host;
+            origins.host = origin;
+        }
+
+        let port_key = format!("{table}.port");
+        if let Ok(port) = config.get_int(&port_key) {
+            source.port = u16::try_from(port).map_err(|_| AhConfigError::InvalidPort {
+                key: port_key.clone(),
+                value: port,
+            })?;
+            origins.port = AhConfig::origin_for_key(&port_key);
+        }
+
+        let format_key = format!("{table}.format");
+        if let Some((format, origin)) = AhConfig::resolve_candidate(config, &[&format_key]) {
+            source.format = format.parse()?;
+            origins.format = origin;
+        }
+
+        Ok((source, origins))
+    }
 
-        let mut ah_config = AhConfig::default();
+    fn get_and_validate_config() -> Result<AhConfig, AhConfigError> {
+        let cli_args = CliArgs::parse();
+        let file_path = AhConfig::resolve_config_file_path(&cli_args);
+        let config = AhConfig::get_config(&file_path)?;
 
-        if let Some(database_url) = config.get("database_url") {
-            ah_config.database_url = database_url.to_string();
+        let mut ah_config = AhConfig {
+            config_file: file_path,
+            ..AhConfig::default()
+        };
+
+        if let Ok(database_url) = config.get_string("database_url") {
+            ah_config.database_url = database_url;
+            ah_config.sources.database_url = AhConfig::origin_for_key("database_url");
         }
 
-        if let Some(enable_acars) = config.get("enable_acars") {
-            ah_config.enable_acars = enable_acars.parse().unwrap();
+        let (acars, acars_origin) =
+            AhConfig::resolve_source(&config, ah_config.acars.clone(), "acars", "enable_acars")?;
+        ah_config.acars = acars;
+        ah_config.sources.acars = acars_origin;
+
+        let (vdlm2, vdlm2_origin) = AhConfig::resolve_source(
+            &config,
+            ah_config.vdlm2.clone(),
+            "vdlm2",
+            "enable_vdlm2",
+        )?;
+        ah_config.vdlm2 = vdlm2;
+        ah_config.sources.vdlm2 = vdlm2_origin;
+
+        let (hfdl, hfdl_origin) =
+            AhConfig::resolve_source(&config, ah_config.hfdl.clone(), "hfdl", "enable_hfdl")?;
+        ah_config.hfdl = hfdl;
+        ah_config.sources.hfdl = hfdl_origin;
+
+        if let Ok(enable_iridium) = config.get_string("enable_iridium") {
+            ah_config.enable_iridium = AhConfig::parse_bool("enable_iridium", &enable_iridium)?;
+            ah_config.sources.enable_iridium = AhConfig::origin_for_key("enable_iridium");
         }
 
-        if let Some(enable_vdlm2) = config.get("enable_vdlm2") {
-            ah_config.enable_vdlm2 = enable_vdlm2.parse().unwrap();
+        if let Ok(enable_inmarsat) = config.get_string("enable_inmarsat") {
+            ah_config.enable_inmarsat = AhConfig::parse_bool("enable_inmarsat", &enable_inmarsat)?;
+            ah_config.sources.enable_inmarsat = AhConfig::origin_for_key("enable_inmarsat");
         }
 
-        if let Some(enable_hfdl) = config.get("enable_hfdl") {
-            ah_config.enable_hfdl = enable_hfdl.parse().unwrap();
+        if let Ok(enable_adsb) = config.get_string("enable_adsb") {
+            ah_config.enable_adsb = AhConfig::parse_bool("enable_adsb", &enable_adsb)?;
+            ah_config.sources.enable_adsb = AhConfig::origin_for_key("enable_adsb");
         }
 
-        if let Some(enable_iridium) = config.get("enable_iridium") {
-            ah_config.enable_iridium = enable_iridium.parse().unwrap();
+        if let Ok(log_level) = config.get_string("log_level") {
+            ah_config.log_level = log_level;
+            ah_config.sources.log_level = AhConfig::origin_for_key("log_level");
         }
 
-        if let Some(enable_inmarsat) = config.get("enable_inmarsat") {
-            ah_config.enable_inmarsat = enable_inmarsat.parse().unwrap();
+        // command line arguments are the highest priority source and override
+        // everything resolved from the defaults, the config file, and the environment
+        if let Some(database_url) = cli_args.database_url {
+            ah_config.database_url = database_url;
+            ah_config.sources.database_url = ConfigOrigin::CommandLine;
         }
 
-        if let Some(enable_adsb) = config.get("enable_adsb") {
-            ah_config.enable_adsb = enable_adsb.parse().unwrap();
+        if let Some(enable_acars) = cli_args.enable_acars {
+            ah_config.acars.enabled = enable_acars;
+            ah_config.sources.acars.enabled = ConfigOrigin::CommandLine;
         }
 
-        if let Some(log_level) = config.get("log_level") {
-            ah_config.log_level = log_level.to_string();
+        if let Some(log_level) = cli_args.log_level {
+            ah_config.log_level = log_level;
+            ah_config.sources.log_level = ConfigOrigin::CommandLine;
         }
 
-        ah_config
+        AhConfig::validate_log_level(&ah_config.log_level)?;
+
+        // only persist back to the file when something actually outranked it (an env var
+        // or a CLI flag) - otherwise every process start would re-dump the whole struct,
+        // permanently baking in ephemeral overrides and clobbering a hand-edited file
+        if ah_config.has_override_origin() {
+            ah_config.write_config()?;
+        }
+
+        Ok(ah_config)
+    }
+
+    /// Logs one source table's fields, each with its own provenance since a table's fields
+    /// can each come from a different source.
+    fn log_source(name: &str, source: &SourceConfig, origins: &SourceOrigins) {
+        info!(
+            "{name}.enabled: {} (from {})",
+            source.enabled, origins.enabled
+        );
+        info!("{name}.host: {} (from {})", source.host, origins.host);
+        info!("{name}.port: {} (from {})", source.port, origins.port);
+        info!(
+            "{name}.format: {} (from {})",
+            source.format, origins.format
+        );
     }
 
     pub fn show_config(&self) {
-        info!("database_url: {}", self.database_url);
-        info!("enable_acars: {}", self.enable_acars);
-        info!("enable_vdlm2: {}", self.enable_vdlm2);
-        info!("enable_hfdl: {}", self.enable_hfdl);
-        info!("enable_iridium: {}", self.enable_iridium);
-        info!("enable_inmarsat: {}", self.enable_inmarsat);
-        info!("enable_adsb: {}", self.enable_adsb);
-        info!("log_level: {}", self.log_level);
+        info!(
+            "database_url: {} (from {})",
+            self.database_url, self.sources.database_url
+        );
+        AhConfig::log_source("acars", &self.acars, &self.sources.acars);
+        AhConfig::log_source("vdlm2", &self.vdlm2, &self.sources.vdlm2);
+        AhConfig::log_source("hfdl", &self.hfdl, &self.sources.hfdl);
+        info!(
+            "enable_iridium: {} (from {})",
+            self.enable_iridium, self.sources.enable_iridium
+        );
+        info!(
+            "enable_inmarsat: {} (from {})",
+            self.enable_inmarsat, self.sources.enable_inmarsat
+        );
+        info!(
+            "enable_adsb: {} (from {})",
+            self.enable_adsb, self.sources.enable_adsb
+        );
+        info!(
+            "log_level: {} (from {})",
+            self.log_level, self.sources.log_level
+        );
     }
 
     pub fn enable_logging(&self) {
         self.log_level.enable_logging();
     }
 
+    /// Escapes a value for embedding in a TOML basic (`"..."`) string, so a `"` or `\`
+    /// coming from a user-supplied `database_url`/`host` can't corrupt the file on write-back.
+    fn escape_toml_string(value: &str) -> String {
+        value.replace('\\', "\\\\").replace('"', "\\\"")
+    }
+
+    fn source_as_toml_table(name: &str, source: &SourceConfig) -> String {
+        format!(
+            "[{name}]\nenabled = {}\nhost = \"{}\"\nport = {}\nformat = \"{}\"\n",
+            source.enabled,
+            AhConfig::escape_toml_string(&source.host),
+            source.port,
+            source.format
+        )
+    }
+
     pub fn get_config_as_toml_string(&self) -> String {
         let mut config = String::new();
 
-        config.push_str(&format!("database_url = \"{}\"\n", self.database_url));
-        config.push_str(&format!("enable_acars = {}\n", self.enable_acars));
-        config.push_str(&format!("enable_vdlm2 = {}\n", self.enable_vdlm2));
-        config.push_str(&format!("enable_hfdl = {}\n", self.enable_hfdl));
+        config.push_str(&format!(
+            "database_url = \"{}\"\n",
+            AhConfig::escape_toml_string(&self.database_url)
+        ));
         config.push_str(&format!("enable_iridium = {}\n", self.enable_iridium));
         config.push_str(&format!("enable_inmarsat = {}\n", self.enable_inmarsat));
         config.push_str(&format!("enable_adsb = {}\n", self.enable_adsb));
         config.push_str(&format!("log_level = \"{}\"\n", self.log_level));
+        config.push('\n');
+        config.push_str(&AhConfig::source_as_toml_table("acars", &self.acars));
+        config.push('\n');
+        config.push_str(&AhConfig::source_as_toml_table("vdlm2", &self.vdlm2));
+        config.push('\n');
+        config.push_str(&AhConfig::source_as_toml_table("hfdl", &self.hfdl));
 
         config
     }
 
-    pub fn write_config(&self) {
-        let file_path = AhConfig::get_file_path();
+    /// Builds one source table's JSON representation. Using `serde_json::json!` rather than
+    /// string concatenation means every value - including a `host` with a `"` or `\` in it -
+    /// comes out properly escaped instead of corrupting the document.
+    fn source_as_json(source: &SourceConfig, origins: &SourceOrigins) -> serde_json::Value {
+        serde_json::json!({
+            "enabled": {"value": source.enabled, "source": origins.enabled.to_string()},
+            "host": {"value": source.host, "source": origins.host.to_string()},
+            "port": {"value": source.port, "source": origins.port.to_string()},
+            "format": {"value": source.format.to_string(), "source": origins.format.to_string()},
+        })
+    }
+
+    /// Serializes the fully-resolved config, including provenance, as JSON - so a future
+    /// status/health endpoint can report the live configuration without reparsing the toml.
+    pub fn get_config_as_json(&self) -> String {
+        serde_json::json!({
+            "database_url": {
+                "value": self.database_url,
+                "source": self.sources.database_url.to_string(),
+            },
+            "acars": AhConfig::source_as_json(&self.acars, &self.sources.acars),
+            "vdlm2": AhConfig::source_as_json(&self.vdlm2, &self.sources.vdlm2),
+            "hfdl": AhConfig::source_as_json(&self.hfdl, &self.sources.hfdl),
+            "enable_iridium": {
+                "value": self.enable_iridium,
+                "source": self.sources.enable_iridium.to_string(),
+            },
+            "enable_inmarsat": {
+                "value": self.enable_inmarsat,
+                "source": self.sources.enable_inmarsat.to_string(),
+            },
+            "enable_adsb": {
+                "value": self.enable_adsb,
+                "source": self.sources.enable_adsb.to_string(),
+            },
+            "log_level": {
+                "value": self.log_level,
+                "source": self.sources.log_level.to_string(),
+            },
+        })
+        .to_string()
+    }
+
+    /// True if at least one field was set from a source higher-priority than the config
+    /// file (an `AH_*` environment variable or a CLI flag), meaning `write_config` needs to
+    /// run for that value to survive into the next start.
+    fn has_override_origin(&self) -> bool {
+        fn is_override(origin: &ConfigOrigin) -> bool {
+            matches!(
+                origin,
+                ConfigOrigin::Environment(_) | ConfigOrigin::CommandLine
+            )
+        }
+
+        let source_is_override = |origins: &SourceOrigins| {
+            is_override(&origins.enabled)
+                || is_override(&origins.host)
+                || is_override(&origins.port)
+                || is_override(&origins.format)
+        };
+
+        is_override(&self.sources.database_url)
+            || source_is_override(&self.sources.acars)
+            || source_is_override(&self.sources.vdlm2)
+            || source_is_override(&self.sources.hfdl)
+            || is_override(&self.sources.enable_iridium)
+            || is_override(&self.sources.enable_inmarsat)
+            || is_override(&self.sources.enable_adsb)
+            || is_override(&self.sources.log_level)
+    }
+
+    pub fn write_config(&self) -> Result<(), AhConfigError> {
         let config = self.get_config_as_toml_string();
 
-        std::fs::write(file_path, config).unwrap();
+        std::fs::write(&self.config_file, config)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    // `std::env::set_var` mutates process-wide state, so tests that touch `AH_*`
+    // environment variables need to run one at a time rather than in parallel with
+    // each other.
+    static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+    fn unique_temp_path(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!(
+                "ah_config_test_{name}_{:?}.toml",
+                std::thread::current().id()
+            ))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[test]
+    fn parse_bool_accepts_true_and_false() {
+        assert_eq!(AhConfig::parse_bool("enable_acars", "true").unwrap(), true);
+        assert_eq!(AhConfig::parse_bool("enable_acars", "false").unwrap(), false);
+    }
+
+    #[test]
+    fn parse_bool_rejects_garbage() {
+        let err = AhConfig::parse_bool("enable_acars", "not-a-bool").unwrap_err();
+        assert!(matches!(err, AhConfigError::InvalidBool { key, value }
+            if key == "enable_acars" && value == "not-a-bool"));
+    }
+
+    #[test]
+    fn validate_log_level_accepts_known_levels() {
+        for level in VALID_LOG_LEVELS {
+            assert!(AhConfig::validate_log_level(level).is_ok());
+        }
+    }
+
+    #[test]
+    fn validate_log_level_rejects_unknown_level() {
+        let err = AhConfig::validate_log_level("verbose").unwrap_err();
+        assert!(matches!(err, AhConfigError::InvalidLogLevel { value } if value == "verbose"));
+    }
+
+    #[test]
+    fn source_format_from_str_roundtrips() {
+        assert_eq!("json".parse::<SourceFormat>().unwrap(), SourceFormat::Json);
+        assert_eq!("raw".parse::<SourceFormat>().unwrap(), SourceFormat::Raw);
+        assert!("xml".parse::<SourceFormat>().is_err());
+    }
+
+    #[test]
+    fn resolve_candidate_prefers_the_highest_ranked_origin() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        std::env::remove_var("AH_ENABLE_ACARS");
+
+        let config = Config::builder()
+            .add_source(config::File::from_str(
+                "enable_acars = \"false\"\nacars.enabled = \"false\"",
+                config::FileFormat::Toml,
+            ))
+            .build()
+            .unwrap();
+
+        // Neither key is set through the environment, so both resolve to `File` origin and
+        // the legacy key (checked first) should win the tie rather than being overwritten by
+        // whichever key happens to be iterated last.
+        let (value, origin) =
+            AhConfig::resolve_candidate(&config, &["enable_acars", "acars.enabled"]).unwrap();
+        assert_eq!(value, "false");
+        assert_eq!(origin, ConfigOrigin::File);
+    }
+
+    #[test]
+    fn resolve_source_rejects_out_of_range_port() {
+        let config = Config::builder()
+            .add_source(config::File::from_str(
+                "acars.port = 99999",
+                config::FileFormat::Toml,
+            ))
+            .build()
+            .unwrap();
+
+        let err = AhConfig::resolve_source(
+            &config,
+            SourceConfig::with_default_port(5550),
+            "acars",
+            "enable_acars",
+        )
+        .unwrap_err();
+
+        assert!(matches!(err, AhConfigError::InvalidPort { key, value }
+            if key == "acars.port" && value == 99999));
+    }
+
+    #[test]
+    fn origin_for_key_reports_environment_only_when_the_var_is_actually_set() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        std::env::remove_var("AH_DATABASE_URL");
+        assert_eq!(AhConfig::origin_for_key("database_url"), ConfigOrigin::File);
+
+        std::env::set_var("AH_DATABASE_URL", "postgres://env");
+        assert_eq!(
+            AhConfig::origin_for_key("database_url"),
+            ConfigOrigin::Environment("AH_DATABASE_URL".to_string())
+        );
+        std::env::remove_var("AH_DATABASE_URL");
+    }
+
+    /// End-to-end: a flat `AH_*` override (no nested separator) must actually reach
+    /// `config-rs` and win over the same key's value in the config file. This is the exact
+    /// path that silently broke when `Environment::separator` was set without also setting
+    /// `prefix_separator`, since `config-rs` reuses `separator` for the prefix join too.
+    #[test]
+    fn flat_env_override_beats_file_value_end_to_end() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        let file_path = unique_temp_path("flat_env_override");
+        std::fs::write(&file_path, "database_url = \"postgres://file\"\n").unwrap();
+        std::env::set_var("AH_DATABASE_URL", "postgres://env");
+
+        let config = AhConfig::get_config(&file_path).unwrap();
+        let (value, origin) =
+            AhConfig::resolve_candidate(&config, &["database_url"]).unwrap();
+
+        std::env::remove_var("AH_DATABASE_URL");
+        std::fs::remove_file(&file_path).ok();
+
+        assert_eq!(value, "postgres://env");
+        assert_eq!(
+            origin,
+            ConfigOrigin::Environment("AH_DATABASE_URL".to_string())
+        );
     }
 }